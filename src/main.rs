@@ -19,7 +19,7 @@ mod staticimp;
 use actix_web::web;
 use actix_web::web::Data;
 use actix_web::FromRequest;
-use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
+use parking_lot::{MappedRwLockReadGuard, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use staticimp::*;
 use std::borrow::Cow;
 use std::collections::HashMap;
@@ -40,47 +40,275 @@ type BackendsData = Data<RwLock<HashMap<String, Mutex<Backend>>>>;
 ///staticimp cryptor (private key for project secrets)
 type CryptorData = Data<Arc<Option<Cryptor>>>;
 
+/// shared (per-worker) http client (e.g. for captcha verification)
+type ClientData = Data<awc::Client>;
+
+/// rate limiter state (shared by all workers)
+type RateLimiterData = Data<RateLimiter>;
+
 /// root handler -- just return hello message
 #[actix_web::get("/")]
 async fn index() -> impl actix_web::Responder {
     format!("Hello from staticimp version {}!\n", &VERSION)
 }
 
+/// public key query args
+#[derive(serde::Deserialize)]
+struct PublicKeyQuery {
+    /// export format (default: pem)
+    #[serde(default)]
+    format: Option<String>,
+}
+
+/// export the current public key
+///
+/// lets site owners encrypt project secrets offline with standard tools (instead of
+/// sending plaintext secrets to the server)
+///
+/// Arguments:
+/// - `format` query arg - `pem` (default) or `jwk`
+#[actix_web::get("/v1/public-key")]
+async fn public_key_handler(
+    cryptor: CryptorData,
+    query: web::Query<PublicKeyQuery>,
+) -> ImpResult<actix_web::HttpResponse> {
+    let cryptor = Option::as_ref(&cryptor)
+        .ok_or_else(|| ImpError::InternalError("", "Key not set".into()))?;
+    let format: PublicKeyFormat = match &query.format {
+        Some(format) => format.parse()?,
+        None => PublicKeyFormat::default(),
+    };
+    let content_type = match format {
+        PublicKeyFormat::Pem => "application/x-pem-file",
+        PublicKeyFormat::Jwk => "application/jwk+json",
+    };
+    Ok(actix_web::HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, content_type))
+        .insert_header((
+            "X-Staticimp-Key-Fingerprint",
+            cryptor.fingerprints().swap_remove(0),
+        ))
+        .body(cryptor.export_public_key(format)?))
+}
+
+/// signed form token for entry forms with spam traps (see [SpamTrapConfig])
+///
+/// the site fetches a token when the entry form is shown, and posts it back with the entry,
+/// so entries posted too quickly after the form was rendered can be caught
+/// - tokens only work for the backend, project, and entry type in the path
+/// - sent with `Access-Control-Allow-Origin: *`, so site scripts can fetch it cross-origin
+#[actix_web::get("/v1/form-token/{backend}/{project:.*}/{entry_type}")]
+async fn form_token_handler(
+    cfg: ConfigData,
+    pathargs: web::Path<(String, String, String)>,
+) -> ImpResult<actix_web::HttpResponse> {
+    let (backend_name, project_id, entry_type) = pathargs.into_inner();
+    if !cfg.backends.contains_key(&backend_name) {
+        return Err(ImpError::BadRequest("", "Unknown backend".into()));
+    }
+    Ok(actix_web::HttpResponse::Ok()
+        .insert_header(ContentType::plaintext())
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoStore]))
+        .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .body(cfg.form_token(&backend_name, &project_id, &entry_type)?))
+}
+
+/// encrypt-secret request (form or json body)
+#[derive(serde::Deserialize)]
+struct EncryptSecretRequest {
+    /// secret value to encrypt
+    secret: String,
+    /// backend of project to scope the secret to (required with project)
+    #[serde(default)]
+    backend: String,
+    /// project to scope the secret to (default: "" -- not scoped)
+    #[serde(default)]
+    project: String,
+    /// token (if not sent in Authorization header)
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// encrypt-secret response
+///
+/// also valid as an encrypted config secret (the extra fields are ignored)
+#[derive(serde::Serialize)]
+struct EncryptSecretResponse {
+    /// base85 ciphertext
+    encrypted: String,
+    /// fingerprint of the key used
+    fingerprint: String,
+    /// backend the secret is scoped to
+    #[serde(skip_serializing_if = "String::is_empty")]
+    backend: String,
+    /// project the secret is scoped to
+    #[serde(skip_serializing_if = "String::is_empty")]
+    project: String,
+}
+
+/// encrypt-secret html form (see [encrypt_secret_form_handler])
+const ENCRYPT_SECRET_FORM: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>staticimp - encrypt secret</title></head>
+<body>
+<h1>Encrypt secret</h1>
+<form method="post" action="encrypt-secret">
+<p><label>Secret<br><textarea name="secret" rows="4" cols="60" required></textarea></label></p>
+<p>Scope to project (optional - the secret will only decrypt in this project's config)</p>
+<p><label>Backend <input name="backend"></label> <label>Project <input name="project"></label></p>
+{token}<p><button type="submit">Encrypt</button></p>
+</form>
+</body>
+</html>
+"#;
+
+/// encrypt-secret html form result page (see [encrypt_secret_handler])
+const ENCRYPT_SECRET_RESULT: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>staticimp - encrypted secret</title></head>
+<body>
+<h1>Encrypted secret</h1>
+<p>Key fingerprint: {fingerprint}<br>Usable in: {scope}</p>
+<pre>secret:
+  encrypted: "{encrypted}"</pre>
+</body>
+</html>
+"#;
+
+/// escape text for html
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// html form for encrypting secrets (if enabled with `encrypt_secret.form`)
+///
+/// the form posts to [encrypt_secret_handler]
+#[actix_web::get("/v1/encrypt-secret")]
+async fn encrypt_secret_form_handler(cfg: ConfigData) -> impl actix_web::Responder {
+    let conf = &cfg.encrypt_secret;
+    if !conf.enabled || !conf.form {
+        return actix_web::HttpResponse::NotFound().finish();
+    }
+    let token = if conf.token.is_empty() {
+        ""
+    } else {
+        "<p><label>Token <input name=\"token\" type=\"password\"></label></p>\n"
+    };
+    actix_web::HttpResponse::Ok()
+        .insert_header(ContentType::html())
+        .body(ENCRYPT_SECRET_FORM.replace("{token}", token))
+}
+
 /// encrypt secret value
-/// 
-/// e.g. for recaptcha secrets
+///
+/// e.g. for captcha secrets
 ///
 /// The staticimp server keeps both the private and public keys.
 /// Encrypted secrets can be stored in project repos so server
 /// can pull encrypted project-specific config from public repos
 ///
 /// Arguments:
-/// - value to encrypt comes from the uri path
-#[actix_web::get("/v1/encrypt-secret/{value:.*}")]
+/// - secret (and optional backend/project scope and token) from the form or json request body
+///   - the secret isn't taken from the url, so it doesn't end up in (proxy) logs
+/// - token can also be sent as `Authorization: Bearer <token>`
+///
+/// Returns [EncryptSecretResponse] as json, or an html page for html form posts
+#[actix_web::post("/v1/encrypt-secret")]
 async fn encrypt_secret_handler(
-    _cfg: ConfigData,
+    cfg: ConfigData,
     cryptor: CryptorData,
-    _req: actix_web::HttpRequest,
-    value: web::Path<String>
-) -> impl actix_web::Responder {
-    let value = value.into_inner(); //secret value to encrypt
-    match cryptor.as_ref().as_ref() {
-        Some(cryptor) =>
-            cryptor.encrypt(value.as_bytes()).map(
-                |encrypted| 
-                actix_web::HttpResponse::build(actix_web::http::StatusCode::OK)
-                //.insert_header(ContentType::html())
-                .insert_header(ContentType::plaintext())
-                //.insert_header(ContentType::form_url_encoded())
-                //.body(form_urlencoded::byte_serialize(&encrypted).collect::<String>())
-                //.body(staticimp::base85::encode(&encrypted))
-                .body(cryptor.decrypt(&staticimp::base85::decode(&staticimp::base85::encode(&encrypted))).unwrap())
-            ),
-        None =>
-            Err(ImpError::InternalError("","Key not set".to_string().into())),
+    content_type: web::Header<header::ContentType>,
+    req: actix_web::HttpRequest,
+    body: actix_web::web::Payload,
+) -> ImpResult<actix_web::HttpResponse> {
+    //unwrap body and content_type
+    let mut body = body.into_inner();
+    let content_type = content_type.0;
+
+    let conf = &cfg.encrypt_secret;
+    if !conf.enabled {
+        return Ok(actix_web::HttpResponse::NotFound().finish());
+    }
+    let cryptor = Option::as_ref(&cryptor)
+        .ok_or_else(|| ImpError::InternalError("", "Key not set".into()))?;
+
+    let request: EncryptSecretRequest = if content_type == ContentType::form_url_encoded() {
+        web::Form::<EncryptSecretRequest>::from_request(&req, &mut body)
+            .await
+            .or_bad_request("Bad Form request")?
+            .into_inner()
+    } else if content_type == ContentType::json() {
+        web::Json::<EncryptSecretRequest>::from_request(&req, &mut body)
+            .await
+            .or_bad_request("Bad json request")?
+            .into_inner()
+    } else {
+        return Err(ImpError::BadRequest("", "Bad Content-Type".into()));
+    };
+
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    conf.authorize(bearer.or(request.token.as_deref()))?;
+
+    if request.secret.is_empty() {
+        return Err(ImpError::BadRequest("", "Missing secret".into()));
+    } else if request.secret.len() > conf.max_length {
+        return Err(ImpError::BadRequest("", "Secret too long".into()));
+    }
+
+    let encrypted = if request.project.is_empty() {
+        if !request.backend.is_empty() {
+            return Err(ImpError::BadRequest("", "Missing project".into()));
+        }
+        cryptor.encrypt(request.secret.as_bytes())?
+    } else {
+        if !cfg.backends.contains_key(&request.backend) {
+            return Err(ImpError::BadRequest("", "Unknown backend".into()));
+        }
+        let scope = Cryptor::project_scope(&request.backend, &request.project);
+        cryptor.encrypt_scoped(request.secret.as_bytes(), &scope)?
+    };
+    let response = EncryptSecretResponse {
+        encrypted: staticimp::base85::encode(&encrypted),
+        fingerprint: cryptor.fingerprints().swap_remove(0),
+        backend: request.backend,
+        project: request.project,
+    };
+
+    //html result page for the html form
+    if conf.form && accepts(&req, "text/html") {
+        let scope = if response.project.is_empty() {
+            "any project".to_string()
+        } else {
+            html_escape(&format!("{} {}", response.backend, response.project))
+        };
+        Ok(actix_web::HttpResponse::Ok()
+            .insert_header(ContentType::html())
+            .body(
+                ENCRYPT_SECRET_RESULT
+                    .replace("{fingerprint}", &response.fingerprint)
+                    .replace("{scope}", &scope)
+                    .replace("{encrypted}", &html_escape(&response.encrypted)),
+            ))
+    } else {
+        Ok(actix_web::HttpResponse::Ok().json(response))
     }
 }
 
+/// check if request `Accept` header includes `mime` type
+fn accepts(req: &actix_web::HttpRequest, mime: &str) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains(mime))
+}
+
 //use staticimp::ImpResult;
 //use staticimp::ImpError;
 //use staticimp::OrImpResult;
@@ -91,56 +319,70 @@ async fn encrypt_secret_handler(
 /// Arguments:
 /// - takes backend,project,branch, and entry type from path
 /// - entry fields taken from request body (based on ContentType)
+///   - `multipart/form-data` bodies can also include file attachments (see [AttachmentConfig])
 /// - params taken from request query parameters
+///
+/// Errors are returned as plain text, or as json (see [ImpError::json_response]) if the
+/// request `Accept` header includes `application/json`
+/// - requests over a rate limit (see [RateLimitConfig]) get 429 with `Retry-After`
 #[actix_web::post("/v1/entry/{backend}/{project:.*}/{branch}/{entry_type}")]
+#[allow(clippy::too_many_arguments)] //handler args are actix extractors
 async fn post_entry_handler(
     cfg: ConfigData,
     backends: BackendsData,
+    cryptor: CryptorData,
+    client: ClientData,
+    limiter: RateLimiterData,
     pathargs: web::Path<(String, String, String, String)>,
     content_type: web::Header<header::ContentType>,
     req: actix_web::HttpRequest,
     body: actix_web::web::Payload,
-) -> impl actix_web::Responder {
+) -> ImpResult<actix_web::HttpResponse> {
+    let json_errors = accepts(&req, "application/json");
+    post_entry(
+        cfg,
+        backends,
+        cryptor,
+        client,
+        limiter,
+        pathargs.into_inner(),
+        content_type.0,
+        &req,
+        body.into_inner(),
+    )
+    .await
+    .or_else(|e| {
+        if json_errors {
+            Ok(e.json_response())
+        } else {
+            Err(e)
+        }
+    })
+}
+
+/// create new entry (for [post_entry_handler])
+#[allow(clippy::too_many_arguments)] //args are the handler's actix extractors
+async fn post_entry(
+    cfg: ConfigData,
+    backends: BackendsData,
+    cryptor: CryptorData,
+    client: ClientData,
+    limiter: RateLimiterData,
+    pathargs: (String, String, String, String),
+    content_type: ContentType,
+    req: &actix_web::HttpRequest,
+    mut body: actix_web::dev::Payload,
+) -> ImpResult<actix_web::HttpResponse> {
     //get path args
-    let pathargs = pathargs.into_inner();
     let backend_name = pathargs.0;
     let project_id = pathargs.1;
     let branch = pathargs.2;
     let entry_type = pathargs.3;
 
-    //unwrap body and content_type
-    let mut body = body.into_inner();
-    let content_type = content_type.0;
-
-
     let query_params = web::Query::<HashMap<String, String>>::from_query(req.query_string())
         .or_bad_request("Bad query args")?
         .into_inner();
 
-    //parse entry from request
-    // supported post formats:
-    // - html form
-    // - json
-    // - yaml (using application/yaml content-type)
-    let entry_fields = if content_type == ContentType::form_url_encoded() {
-        web::Form::<EntryFields>::from_request(&req, &mut body)
-            .await
-            .or_bad_request("Bad Form entry")?
-            .into_inner()
-    } else if content_type == ContentType::json() {
-        let body = web::Bytes::from_request(&req, &mut body)
-            .await
-            .or_bad_request("Bad payload")?;
-        serde_json::from_slice(&body).or_bad_request("Bad json entry")?
-    } else if content_type.to_string() == "application/yaml" {
-        let body = web::Bytes::from_request(&req, &mut body)
-            .await
-            .or_bad_request("Bad payload")?;
-        serde_yaml::from_slice::<EntryFields>(&body).or_bad_request("Bad yaml entry")?
-    } else {
-        return Err(ImpError::BadRequest("", "Bad Content-Type".into()));
-    };
-
     let backend_conf = cfg
         .backends
         .get(&backend_name)
@@ -171,58 +413,291 @@ async fn post_entry_handler(
         }
     }
 
-    // get backend client to use
-    // - first we get a read lock on backends
-    //   - we hold the read lock for the rest of this function to keep the client mutex borrow valid
-    // - if we already have a backend client for backend_name, return the mutex for it
-    // - else if we don't already have the client, but we do have a backend config for it
-    //   - get a write lock (dropping the read lock)
-    //   - create a new client and insert it
-    //   - acquire a new read lock (inside the write lock to avoid blocking)
-    //   - drop write lock (by leaving scope)
-    //   - return backend client mutex from map
-    // - else return an error (unknown backend)
-    let mut lock = backends.read();
-    let backend = if let Some(backend) = lock.get(&backend_name) {
-        backend
+    //backend and project rate limits are checked before fetching the project config
+    limiter.check(&backend_conf.rate_limits(&backend_name, &project_id, client_addr))?;
+
+    // get entry conf to use (from project if enabled)
+    // - the backends read lock is dropped before reading the request body (see [backend_client])
+    let mut entry_conf = get_entry_conf(
+        &cfg,
+        &mut backend_client(&backends, &cfg, &backend_name).await?.lock(),
+        &backend_name,
+        Option::as_ref(&cryptor),
+        &project_id,
+        &branch,
+        &entry_type,
+    )
+    .await?;
+
+    //entry type rate limits (which can come from the project config)
+    limiter.check(&entry_conf.rate_limits(&backend_name, &project_id, &entry_type, client_addr))?;
+
+    //parse entry from request (after getting the entry conf, for the attachment limits)
+    // supported post formats:
+    // - html form
+    // - multipart form (with attachments)
+    // - json
+    // - yaml (using application/yaml content-type)
+    let (mut entry_fields, attachments) = if content_type == ContentType::form_url_encoded() {
+        let fields = web::Form::<EntryFields>::from_request(req, &mut body)
+            .await
+            .or_bad_request("Bad Form entry")?
+            .into_inner();
+        (fields, Vec::new())
+    } else if content_type.0.essence_str() == "multipart/form-data" {
+        read_multipart(req, body, &entry_conf.attachments).await?
+    } else if content_type == ContentType::json() {
+        let body = web::Bytes::from_request(req, &mut body)
+            .await
+            .or_bad_request("Bad payload")?;
+        let fields = serde_json::from_slice(&body).or_bad_request("Bad json entry")?;
+        (fields, Vec::new())
+    } else if content_type.to_string() == "application/yaml" {
+        let body = web::Bytes::from_request(req, &mut body)
+            .await
+            .or_bad_request("Bad payload")?;
+        let fields =
+            serde_yaml::from_slice::<EntryFields>(&body).or_bad_request("Bad yaml entry")?;
+        (fields, Vec::new())
     } else {
+        return Err(ImpError::BadRequest("", "Bad Content-Type".into()));
+    };
+
+    //spam traps are checked (and their fields stripped) before captcha and field validation
+    if let Err(e) = cfg.check_spam_traps(
+        &entry_conf,
+        &mut entry_fields,
+        &backend_name,
+        &project_id,
+        &entry_type,
+    ) {
+        return match entry_conf.spam_traps.action {
+            SpamTrapAction::Reject => Err(e),
+            SpamTrapAction::Discard => Ok(actix_web::HttpResponse::Ok().finish()),
+        };
+    }
+
+    //captcha responses aren't part of the entry (so strip them before field validation)
+    let captcha_responses = entry_fields.remove_captcha_responses();
+
+    if entry_conf.captcha_enabled() {
+        let response = captcha_responses
+            .get(entry_conf.captcha.response_field())
+            .ok_or_else(|| ImpError::BadRequest("", "Missing captcha response".into()))?;
+        entry_conf
+            .captcha
+            .verify(&client, response, client_addr)
+            .await?;
+    }
+
+    //content filters check the posted fields (before transforms), and can force review
+    let filtered = entry_conf.filters.check(&entry_fields)?;
+    if filtered.review {
+        entry_conf.to_mut().force_review();
+    }
+
+    //the spam check gets the posted fields (before transforms, e.g. unhashed emails)
+    let posted_fields = entry_conf.akismet.enabled.then(|| entry_fields.clone());
+
+    //create the NewEntry, attach uploaded files, process the entry fields, and add filter tags
+    let newentry = cfg
+        .new_entry(project_id, branch, entry_type, entry_fields, query_params)
+        .attach(&entry_conf.attachments, attachments)?
+        .process_fields(entry_conf.field_config())?
+        .tag(&entry_conf.filters.tag_field, filtered.tags);
+
+    //spam check (after the entry passes validation), spam is rejected or sent for review
+    if let Some(fields) = posted_fields {
+        let header_str = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let request = SpamCheckRequest {
+            user_ip: client_addr,
+            user_agent: header_str(header::USER_AGENT),
+            referrer: header_str(header::REFERER),
+        };
+        if entry_conf
+            .akismet
+            .check(&client, &newentry, &fields, &request)
+            .await?
+        {
+            entry_conf.to_mut().force_review();
+        }
+    }
+
+    //send new entry to backend
+    backend_client(&backends, &cfg, &backend_name)
+        .await?
+        .lock()
+        .new_entry(&entry_conf, newentry)
+        .await?;
+    Ok(actix_web::HttpResponse::Ok().finish())
+}
+
+/// get backend client for `backend_name`, creating it if needed
+///
+/// returns the client mutex inside a read lock on `backends`
+/// - don't hold it while waiting on the client (e.g. reading the request body),
+///   since creating another backend's client needs the write lock
+///
+/// - first we get a read lock on backends
+/// - if we already have a backend client for backend_name, return the mutex for it
+/// - else if we don't already have the client, but we do have a backend config for it
+///   - get a write lock (dropping the read lock)
+///   - create a new client and insert it
+///   - acquire a new read lock (inside the write lock to avoid blocking)
+///   - drop write lock (by leaving scope)
+///   - return backend client mutex from map
+/// - else return an error (unknown backend)
+async fn backend_client<'a>(
+    backends: &'a BackendsData,
+    cfg: &Config,
+    backend_name: &str,
+) -> ImpResult<MappedRwLockReadGuard<'a, Mutex<Backend>>> {
+    let backend_conf = cfg
+        .backends
+        .get(backend_name)
+        .ok_or_else(|| ImpError::BadRequest("", "Unknown backend".into()))?;
+    let mut lock = backends.read();
+    if !lock.contains_key(backend_name) {
         drop(lock); //drop read lock (so we can acquire write lock)
         lock = {
             //acquire write lock
             let mut write = backends.write();
             //confirm no-one just added the client before we relocked
-            if !write.contains_key(&backend_name) {
+            if !write.contains_key(backend_name) {
                 //insert new backend client using write lock
                 write.insert(
-                    backend_name.clone(),
-                    Mutex::from(backend_conf.new_client().await?),
+                    backend_name.to_string(),
+                    Mutex::from(backend_conf.new_client(&cfg.backends).await?),
                 );
             }
             //return new readlock (obtained inside write lock), dropping write lock
             RwLockWriteGuard::downgrade(write)
         };
-        //return the newly added backend client (using the read lock)
-        lock.get(&backend_name).unwrap()
-    };
+    }
+    Ok(RwLockReadGuard::map(lock, |backends| {
+        &backends[backend_name]
+    }))
+}
 
-    // get entry conf to use (from project if enabled)
-    // - first try project_conf_path if set
-    // - fall back to global conf entry types
-    // - entry conf in Cow so we don't need to clone global entry conf
-    //   - borrowed from global conf or owned from project conf
-    //   - TODO: cache project confs (with project specific cache timeout)
-    let entry_conf = backend
-        .lock()
-        .get_conf(&backend_conf, &project_id, &branch)
+/// max size of the text fields in a multipart entry (same as the default actix payload limit)
+const MULTIPART_TEXT_LIMIT: usize = 262_144;
+
+/// read multipart/form-data entry fields and attachments
+///
+/// - parts with a file name are attachments, other parts are text fields
+///   - empty file inputs (no file selected) are skipped
+/// - attachment limits are checked while reading, so oversized uploads are rejected early
+async fn read_multipart(
+    req: &actix_web::HttpRequest,
+    body: actix_web::dev::Payload,
+    conf: &AttachmentConfig,
+) -> ImpResult<(EntryFields, Vec<Attachment>)> {
+    use futures::TryStreamExt;
+    let mut multipart = actix_multipart::Multipart::new(req.headers(), body);
+    let mut fields = EntryFields::default();
+    let mut attachments = Vec::new();
+    let (mut text_size, mut total_size) = (0, 0);
+    while let Some(mut part) = multipart
+        .try_next()
+        .await
+        .or_bad_request("Bad multipart entry")?
+    {
+        let name = part
+            .name()
+            .ok_or_else(|| ImpError::BadRequest("", "Bad multipart entry".into()))?
+            .to_string();
+        let filename = part
+            .content_disposition()
+            .and_then(|disposition| disposition.get_filename())
+            .map(str::to_string);
+        let mut data = Vec::new();
+        match filename {
+            None => {
+                while let Some(chunk) = part
+                    .try_next()
+                    .await
+                    .or_bad_request("Bad multipart entry")?
+                {
+                    text_size += chunk.len();
+                    if text_size > MULTIPART_TEXT_LIMIT {
+                        return Err(ImpError::BadRequest("", "Entry too large".into()));
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                let value = String::from_utf8(data).or_bad_request("Bad multipart entry")?;
+                fields.insert(name, value.into());
+            }
+            Some(filename) if filename.is_empty() => continue,
+            Some(filename) => {
+                let content_type = part
+                    .content_type()
+                    .map_or("application/octet-stream", |mime| mime.essence_str())
+                    .to_string();
+                conf.check_file(&name, attachments.len(), &content_type)?;
+                while let Some(chunk) = part
+                    .try_next()
+                    .await
+                    .or_bad_request("Bad multipart entry")?
+                {
+                    total_size += chunk.len();
+                    data.extend_from_slice(&chunk);
+                    conf.check_size(&name, data.len(), total_size)?;
+                }
+                attachments.push(Attachment::new(&name, &filename, &content_type, data));
+            }
+        }
+    }
+    Ok((fields, attachments))
+}
+
+/// get entry conf to use (from project if enabled)
+///
+/// - first try project_conf_path if set
+/// - fall back to global conf entry types
+/// - project entry conf secrets get decrypted with `cryptor` (global conf is decrypted at load)
+///   - only secrets scoped to this project (see [Cryptor::project_scope]) are allowed
+/// - project entry confs can't change server-only settings (see [EntryConfig::check_project_config])
+/// - entry conf in Cow so we don't need to clone global entry conf
+///   - borrowed from global conf or owned from project conf
+///   - TODO: cache project confs (with project specific cache timeout)
+async fn get_entry_conf<'a>(
+    cfg: &'a Config,
+    backend: &mut Backend,
+    backend_name: &str,
+    cryptor: Option<&Cryptor>,
+    project_id: &str,
+    branch: &str,
+    entry_type: &str,
+) -> ImpResult<Cow<'a, EntryConfig>> {
+    let backend_conf = cfg
+        .backends
+        .get(backend_name)
+        .ok_or_else(|| ImpError::BadRequest("", "Unknown backend".into()))?;
+    let scope = Cryptor::project_scope(backend_name, project_id);
+    backend
+        .get_conf(backend_conf, project_id, branch)
         .await?
         //all we need is the current entry type (not all entries)
-        .and_then(|mut conf| conf.entries.remove(&entry_type))
+        .and_then(|mut conf| conf.entries.remove(entry_type))
+        //check server-only settings and decrypt project secrets
+        .map(|mut conf| {
+            conf.check_project_config()?;
+            conf.attachments.limit(cfg.max_attachment_size);
+            conf.decrypt_secrets(cryptor, Some(&scope)).map(|_| conf)
+        })
+        .transpose()?
         //wrap it in an Owned Cow (since it was fetched from project conf, not borrowed from server conf)
         .and_then(|conf| Some(Cow::Owned(conf)))
         .or_else(||
             // try global entry config (and wrap in Cow)
             cfg.entries
-                .get(&entry_type)
+                .get(entry_type)
                 .and_then(|conf| Some(Cow::Borrowed(conf))))
         .and_then(|conf| {
             //if entry type is disabled, error on unknown entry
@@ -236,28 +711,216 @@ async fn post_entry_handler(
         .ok_or(ImpError::BadRequest("", "Unknown entry type".into()))
         .and_then(|conf| {
             //validate that the target branch is allowed by entry conf
-            if conf.validate_branch(&branch) {
+            if conf.validate_branch(branch) {
                 Ok(conf)
             } else {
                 Err(ImpError::BadRequest("", "Invalid entry branch".into()))
             }
-        })?;
+        })
+}
+
+/// entry management action (from program arguments)
+enum EntryAction {
+    /// list entry paths under a path prefix
+    List { prefix: String },
+    /// find entry path by `_id` field (under a path prefix)
+    Find { prefix: String, id: String },
+    /// print entry fields
+    Get { path: String },
+    /// delete entry
+    Delete { path: String },
+}
+
+/// entry management command (e.g. for moderation, or deleting entries on request)
+///
+/// runs against a configured backend and exits instead of starting the server
+struct EntryCommand {
+    backend: String,
+    project: String,
+    branch: String,
+    entry_type: String,
+    action: EntryAction,
+}
 
-    if entry_conf.recaptcha_enabled() {
-        //if !entry_conf.recaptcha.verify(client, response, client_addr).await? {
-        //    return Err(ImpError::BadRequest("", "Recaptcha failed".into()));
-        //}
-        todo!("Validate Recaptcha if enabled"); //FIXME: validate
+impl EntryCommand {
+    /// parse entry command from program arguments
+    ///
+    /// - `command` - the command argument (e.g. `--list-entries`)
+    /// - `args` - remaining program arguments
+    fn parse(command: &str, args: &mut impl Iterator<Item = String>) -> ImpResult<Self> {
+        let mut next = |name: &str| {
+            args.next().ok_or_else(|| {
+                ImpError::InternalError("", format!("Missing {} for {}", name, command).into())
+            })
+        };
+        let (backend, project, branch, entry_type) = (
+            next("backend")?,
+            next("project")?,
+            next("branch")?,
+            next("entry type")?,
+        );
+        let action = match command {
+            "--list-entries" => EntryAction::List {
+                prefix: next("prefix")?,
+            },
+            "--find-entry" => EntryAction::Find {
+                prefix: next("prefix")?,
+                id: next("id")?,
+            },
+            "--get-entry" => EntryAction::Get {
+                path: next("path")?,
+            },
+            _ => EntryAction::Delete {
+                path: next("path")?,
+            },
+        };
+        Ok(Self {
+            backend,
+            project,
+            branch,
+            entry_type,
+            action,
+        })
     }
 
-    //create the NewEntry and process the entry fields
-    let newentry = cfg
-        .new_entry(project_id, branch, entry_fields, query_params)
-        .process_fields(entry_conf.field_config())?;
+    /// run entry command, returning output to print
+    async fn run(&self, cfg: &Config, cryptor: Option<&Cryptor>) -> ImpResult<String> {
+        let backend_conf = cfg
+            .backends
+            .get(&self.backend)
+            .ok_or_else(|| ImpError::BadRequest("", "Unknown backend".into()))?;
+        let mut backend = backend_conf.new_client(&cfg.backends).await?;
+        let entry_conf = get_entry_conf(
+            cfg,
+            &mut backend,
+            &self.backend,
+            cryptor,
+            &self.project,
+            &self.branch,
+            &self.entry_type,
+        )
+        .await?;
+        let (project, branch) = (self.project.as_str(), self.branch.as_str());
+        match &self.action {
+            EntryAction::List { prefix } => Ok(backend
+                .list_entries(&self.entry_type, project, branch, prefix)
+                .await?
+                .into_iter()
+                .map(|path| path + "\n")
+                .collect()),
+            EntryAction::Find { prefix, id } => backend
+                .find_entry(&entry_conf, &self.entry_type, project, branch, prefix, id)
+                .await?
+                .map(|path| path + "\n")
+                .ok_or_else(|| ImpError::BadRequest("", "Entry not found".into())),
+            EntryAction::Get { path } => {
+                let fields = backend
+                    .get_entry(&entry_conf, project, branch, path)
+                    .await?;
+                SerializationFormat::Yaml.serialize_pretty(&fields)
+            }
+            EntryAction::Delete { path } => {
+                backend
+                    .delete_entry(&entry_conf, project, branch, path)
+                    .await?;
+                Ok(format!("Deleted {}\n", path))
+            }
+        }
+    }
+}
 
-    //send new entry to backend
-    backend.lock().new_entry(&entry_conf, newentry).await?;
-    Ok(actix_web::HttpResponse::Ok().finish())
+/// command to run instead of starting the server (from program arguments)
+enum Command {
+    /// entry management command
+    Entry(EntryCommand),
+    /// re-encrypt a base85 encrypted secret with the current key (e.g. after key rotation)
+    ///
+    /// `scope` - (backend, project) the secret may be scoped to
+    ReencryptSecret {
+        secret: String,
+        scope: Option<(String, String)>,
+    },
+    /// encrypt a secret with the current key, or with a PEM public key file
+    ///
+    /// `scope` - (backend, project) to scope the secret to
+    EncryptSecret {
+        secret: String,
+        scope: Option<(String, String)>,
+        public_key_file: Option<String>,
+    },
+    /// list fingerprints of the loaded keys
+    ListKeys,
+    /// print the current public key
+    PublicKey(PublicKeyFormat),
+}
+
+impl Command {
+    /// run command, returning output to print
+    async fn run(&self, cfg: &Config, cryptor: Option<&Cryptor>) -> ImpResult<String> {
+        let key_not_set = || ImpError::InternalError("", "Key not set".into());
+        match self {
+            Command::Entry(command) => command.run(cfg, cryptor).await,
+            Command::ReencryptSecret { secret, scope } => {
+                let cryptor = cryptor.ok_or_else(key_not_set)?;
+                let scope = scope
+                    .as_ref()
+                    .map(|(backend, project)| Cryptor::project_scope(backend, project));
+                let reencrypted =
+                    cryptor.reencrypt(&Cryptor::decode_text(secret)?, scope.as_deref())?;
+                Ok(staticimp::base85::encode(&reencrypted) + "\n")
+            }
+            Command::EncryptSecret { .. } => self.encrypt_secret(cryptor),
+            Command::PublicKey(format) => {
+                let cryptor = cryptor.ok_or_else(key_not_set)?;
+                Ok(cryptor.export_public_key(*format)? + "\n")
+            }
+            Command::ListKeys => {
+                let cryptor = cryptor.ok_or_else(key_not_set)?;
+                Ok(cryptor
+                    .fingerprints()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, fingerprint)| {
+                        fingerprint
+                            + if i == 0 {
+                                " (current)\n"
+                            } else {
+                                " (retired)\n"
+                            }
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// run [Command::EncryptSecret]
+    ///
+    /// only needs `cryptor` if no public key file is set
+    fn encrypt_secret(&self, cryptor: Option<&Cryptor>) -> ImpResult<String> {
+        let Command::EncryptSecret {
+            secret,
+            scope,
+            public_key_file,
+        } = self
+        else {
+            return Err(ImpError::InternalError("", "Not an encrypt command".into()));
+        };
+        let scope = scope
+            .as_ref()
+            .map(|(backend, project)| Cryptor::project_scope(backend, project));
+        let encrypted = match (public_key_file, cryptor) {
+            (Some(path), _) => {
+                let pem = std::fs::read(path).or_internal_error("Couldn't open public key file")?;
+                Cryptor::encrypt_with_public_key(&pem, secret.as_bytes(), scope.as_deref())?
+            }
+            (None, Some(cryptor)) => match &scope {
+                Some(scope) => cryptor.encrypt_scoped(secret.as_bytes(), scope)?,
+                None => cryptor.encrypt(secret.as_bytes())?,
+            },
+            (None, None) => return Err(ImpError::InternalError("", "Key not set".into())),
+        };
+        Ok(staticimp::base85::encode(&encrypted) + "\n")
+    }
 }
 
 /// Load staticimp config from file/stdin
@@ -270,12 +933,32 @@ async fn post_entry_handler(
 /// - `--yaml | --yml` - config is yaml
 ///   - this is the default unless path ends in ".json"
 /// - `--json` - config is json
-fn load_config() -> ImpResult<(staticimp::Config,Option<Cryptor>)> {
+/// - `--gen-key` - generate new key (see [Config::get_cryptor])
+/// - `--key-type <rsa|p256|x25519>` - key type for `--gen-key` (default: rsa)
+///
+/// entry commands run against a backend and exit (see [EntryCommand]):
+/// - `--list-entries <backend> <project> <branch> <entry_type> <prefix>` - list entry paths
+/// - `--find-entry <backend> <project> <branch> <entry_type> <prefix> <id>` - find entry by `_id`
+/// - `--get-entry <backend> <project> <branch> <entry_type> <path>` - print entry fields
+/// - `--delete-entry <backend> <project> <branch> <entry_type> <path>` - delete entry
+///
+/// key commands (see [Command]):
+/// - `--reencrypt-secret <secret>` - re-encrypt secret with the current key
+/// - `--reencrypt-project-secret <backend> <project> <secret>` - re-encrypt secret that may be scoped to project
+/// - `--encrypt-secret <secret>` - encrypt secret with the current key
+/// - `--encrypt-project-secret <backend> <project> <secret>` - encrypt secret scoped to project
+/// - `--public-key-file <path>` - encrypt with a PEM public key instead (no server config or key needed)
+/// - `--list-keys` - list key fingerprints
+/// - `--public-key <pem|jwk>` - print current public key
+fn load_config() -> ImpResult<(staticimp::Config, Option<Cryptor>, Option<Command>)> {
     use staticimp::SerializationFormat::{Json, Yaml};
     let mut config_path = "staticimp.yml".to_string();
     let mut config_format = None;
     let mut print_config = false;
     let mut gen_key = false;
+    let mut key_type = KeyType::default();
+    let mut command = None;
+    let mut public_key_file = None;
 
     let mut args = std::env::args().skip(1); //skip program path
 
@@ -291,8 +974,66 @@ fn load_config() -> ImpResult<(staticimp::Config,Option<Cryptor>)> {
             config_format = Some(Json);
         } else if arg == "--gen-key" { //generate new key file (error if it already exists)
             gen_key = true;
+        } else if arg == "--key-type" {
+            //key type for --gen-key
+            key_type = args
+                .next()
+                .ok_or_else(|| ImpError::InternalError("", "Missing key type".into()))?
+                .parse()?;
         } else if arg == "--print-config" { //print config and exit
             print_config = true;
+        } else if [
+            "--list-entries",
+            "--find-entry",
+            "--get-entry",
+            "--delete-entry",
+        ]
+        .contains(&arg.as_str())
+        {
+            command = Some(Command::Entry(EntryCommand::parse(&arg, &mut args)?));
+        } else if arg == "--reencrypt-secret" || arg == "--reencrypt-project-secret" {
+            let mut next = |name: &str| {
+                args.next().ok_or_else(|| {
+                    ImpError::InternalError("", format!("Missing {} for {}", name, arg).into())
+                })
+            };
+            let scope = if arg == "--reencrypt-project-secret" {
+                Some((next("backend")?, next("project")?))
+            } else {
+                None
+            };
+            let secret = next("secret")?;
+            command = Some(Command::ReencryptSecret { secret, scope });
+        } else if arg == "--encrypt-secret" || arg == "--encrypt-project-secret" {
+            let mut next = |name: &str| {
+                args.next().ok_or_else(|| {
+                    ImpError::InternalError("", format!("Missing {} for {}", name, arg).into())
+                })
+            };
+            let scope = if arg == "--encrypt-project-secret" {
+                Some((next("backend")?, next("project")?))
+            } else {
+                None
+            };
+            let secret = next("secret")?;
+            command = Some(Command::EncryptSecret {
+                secret,
+                scope,
+                public_key_file: None,
+            });
+        } else if arg == "--public-key-file" {
+            //public key for --encrypt-secret
+            public_key_file =
+                Some(args.next().ok_or_else(|| {
+                    ImpError::InternalError("", "Missing public key file".into())
+                })?);
+        } else if arg == "--list-keys" {
+            command = Some(Command::ListKeys);
+        } else if arg == "--public-key" {
+            let format = args
+                .next()
+                .ok_or_else(|| ImpError::InternalError("", "Missing public key format".into()))?;
+            command = Some(Command::PublicKey(format.parse()?));
         } else {
             return Err(ImpError::InternalError(
                 "",
@@ -301,6 +1042,24 @@ fn load_config() -> ImpResult<(staticimp::Config,Option<Cryptor>)> {
         }
     }
 
+    if let Some(Command::EncryptSecret {
+        public_key_file: file,
+        ..
+    }) = &mut command
+    {
+        *file = public_key_file;
+        //encrypting with a public key file doesn't need the server config, so print and exit
+        if file.is_some() {
+            print!("{}", command.as_ref().unwrap().encrypt_secret(None)?);
+            std::process::exit(0);
+        }
+    } else if public_key_file.is_some() {
+        return Err(ImpError::InternalError(
+            "",
+            "--public-key-file is only used with --encrypt-secret".into(),
+        ));
+    }
+
     // if config_format not specified in args, determine format from path
     let config_format =
         config_format.unwrap_or_else(|| SerializationFormat::from_path(&config_path));
@@ -314,50 +1073,105 @@ fn load_config() -> ImpResult<(staticimp::Config,Option<Cryptor>)> {
         //else load from file
         Config::load(&config_path, config_format).and_then(|cfg| Ok(cfg.env_override()))
     }
-    .and_then(|conf| {
+    .and_then(|mut conf| {
         if print_config {
             //we use a debug error to print the config and exit
             Err(ImpError::debug(config_format.serialize_pretty(&conf)?))
         } else {
-            let cryptor = conf.get_cryptor(gen_key)?;
-            Ok((conf,cryptor))
+            let cryptor = conf.get_cryptor(gen_key.then_some(key_type))?;
+            conf.decrypt_secrets(cryptor.as_ref())?;
+            conf.init_form_token_secret()?;
+            conf.init_backends()?;
+            Ok((conf, cryptor, command))
         }
     })
 }
 
+/// print error (or debug output) and exit
+fn exit_error<T>(e: ImpError) -> T {
+    match e {
+        ImpError::Debug(s) => println!("{}", s),
+        e => eprintln!("{}", e),
+    }
+    std::process::exit(1);
+}
+
 //main - load config and start HttpServer
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let (cfg,cryptor) = load_config().unwrap_or_else(|e| {
-        match e {
-            ImpError::Debug(s) => println!("{}", s),
-            e => eprintln!("{}", e),
-        }
-        std::process::exit(1);
-    });
+    let (cfg, cryptor, command) = load_config().unwrap_or_else(exit_error);
+
+    //run command instead of server if requested
+    if let Some(command) = command {
+        print!(
+            "{}",
+            command
+                .run(&cfg, cryptor.as_ref())
+                .await
+                .unwrap_or_else(exit_error)
+        );
+        return Ok(());
+    }
 
     //wrap Config in ConfigData for actix worker threads
     let cfg = ConfigData::new(Arc::new(cfg));
 
     let cryptor = CryptorData::new(Arc::new(cryptor));
 
+    //load rate limiter state (from the last snapshot, if enabled)
+    let snapshot_path = cfg.rate_limiter.snapshot_path.clone();
+    let limiter =
+        RateLimiterData::new(RateLimiter::load(&snapshot_path).unwrap_or_else(exit_error));
+    {
+        //drop refilled rate limit buckets periodically (off the request path)
+        // - and save snapshots (in case the server doesn't shut down cleanly), which also prunes
+        let limiter = limiter.clone();
+        let snapshot_path = snapshot_path.clone();
+        let period = std::time::Duration::from_secs(cfg.rate_limiter.snapshot_interval.max(1));
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(period);
+            interval.tick().await; //first tick is immediate
+            loop {
+                interval.tick().await;
+                if snapshot_path.is_empty() {
+                    limiter.prune();
+                } else if let Err(e) = limiter.save(&snapshot_path) {
+                    eprintln!("{}", e);
+                }
+            }
+        });
+    }
+
     //let backends : HashMap<String,Backend> = cfg.backends.iter().map(|(k,v)| (k,v.new_client().await?)).collect();
     //let backends = BackendsData::new(Box::new(backends));
     let backends = BackendsData::new(RwLock::from(HashMap::new())); //let threads create clients as-needed
     let host = cfg.host.clone();
     let port = cfg.port;
+    let snapshot_limiter = limiter.clone();
 
     actix_web::HttpServer::new(move || {
         actix_web::App::new()
             .app_data(cfg.clone())
             .app_data(cryptor.clone())
             .app_data(backends.clone())
-            //.app_data(Data::new(awc::Client::new()))
+            .app_data(limiter.clone())
+            .app_data(ClientData::new(awc::Client::default()))
             .service(index)
+            .service(public_key_handler)
+            .service(encrypt_secret_form_handler)
             .service(encrypt_secret_handler)
+            .service(form_token_handler)
             .service(post_entry_handler)
     })
     .bind((host.as_str(), port))?
     .run()
-    .await
+    .await?;
+
+    //save the rate limiter state on shutdown
+    if !snapshot_path.is_empty() {
+        if let Err(e) = snapshot_limiter.save(&snapshot_path) {
+            eprintln!("{}", e);
+        }
+    }
+    Ok(())
 }